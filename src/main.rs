@@ -1,21 +1,54 @@
 use actix_files::NamedFile;
 use actix_multipart::Multipart;
-use actix_web::{get, post, web, App, HttpServer, HttpResponse, Result}; 
+use actix_web::{get, post, web, App, HttpServer, HttpRequest, HttpResponse, Result};
+use actix_web::http::header;
+use bytes::Bytes;
 use futures_util::stream::TryStreamExt;
 use image::{ImageFormat, io::Reader as ImageReader};
+use sha2::{Digest, Sha256};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
 use serde::Deserialize;
 use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use actix_cors::Cors;
+use tokio::sync::mpsc;
 
+mod process;
+use process::{apply_chain, canonical_key, parse_chain};
+
+mod queue;
+use queue::{generate_delete_token, tokens_match, ConvertJob, TaskRegistry, TaskStatus};
+
+mod store;
+use store::{build_store, finalize_local, materialize_local, unique_scratch_path, Store};
 
 struct AppState {
-    task_id_counter: Mutex<i32>,
+    db: sled::Db,
+    tasks: Arc<TaskRegistry>,
+    next_task_id: AtomicU64,
+    job_tx: mpsc::Sender<ConvertJob>,
+    store: Arc<dyn Store>,
 }
 
+const MEGABYTES: usize = 1024 * 1024;
+const MAX_UPLOAD_MEGABYTES: usize = 25;
+const MAX_UPLOAD_BYTES: usize = MAX_UPLOAD_MEGABYTES * MEGABYTES;
+
+/// Formats we trust as *input*; sniffed from magic bytes, not the client-supplied
+/// filename or content-type. `Ico` is deliberately excluded — it's an output-only
+/// target produced via `thumbnail`, not something we accept uploads as.
+const ACCEPTED_INPUT_FORMATS: [ImageFormat; 6] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::Bmp,
+    ImageFormat::WebP,
+    ImageFormat::Tiff,
+];
+
 #[derive(Deserialize)]
 struct ConvertParams {
     output_format: String,
@@ -28,68 +61,336 @@ async fn convert_image_endpoint(
     query: web::Query<ConvertParams>,
 ) -> Result<HttpResponse> {
     let output_format = &query.output_format;
-    let mut file_path: Option<PathBuf> = None;
-    let mut original_filename = String::new();
+    if output_format_to_image_format(output_format).is_none() {
+        return Ok(HttpResponse::BadRequest().body("Unsupported output format"));
+    }
+
+    let mut stored_key: Option<String> = None;
+    let mut content_hash = String::new();
 
     while let Some(mut field) = payload.try_next().await? {  // Declare field as mutable
-        if let Some(filename) = field.content_disposition().get_filename() {
-            original_filename = filename.to_string();
-            let filepath = Path::new("uploads").join(&filename);
-            let mut f = fs::File::create(filepath.clone()).await?;
-            
+        if field.content_disposition().get_filename().is_some() {
+            let tmp_path = Path::new("uploads").join(format!(".tmp-{}", uuid_like()));
+            let mut f = fs::File::create(&tmp_path).await?;
+            let mut hasher = Sha256::new();
+            let mut bytes_written: usize = 0;
+
             while let Some(chunk) = field.try_next().await? {  // This will now work
+                bytes_written += chunk.len();
+                if bytes_written > MAX_UPLOAD_BYTES {
+                    drop(f);
+                    fs::remove_file(&tmp_path).await.ok();
+                    return Ok(HttpResponse::PayloadTooLarge()
+                        .body(format!("Upload exceeds the {}MB limit", MAX_UPLOAD_MEGABYTES)));
+                }
+                hasher.update(&chunk);
                 f.write_all(&chunk).await?;
             }
             f.sync_all().await?;
-            file_path = Some(filepath);
+
+            let guessed_format = ImageReader::open(&tmp_path)
+                .ok()
+                .and_then(|r| r.with_guessed_format().ok())
+                .and_then(|r| r.format());
+            if !guessed_format.is_some_and(|f| ACCEPTED_INPUT_FORMATS.contains(&f)) {
+                fs::remove_file(&tmp_path).await.ok();
+                return Ok(HttpResponse::UnsupportedMediaType()
+                    .body("Upload is not a recognized PNG/JPEG/GIF/BMP/WEBP/TIFF image"));
+            }
+
+            let hash = hex::encode(hasher.finalize());
+            let upload_key = format!("uploads/{}", hash);
+
+            if !state.store.exists(&upload_key).await {
+                let data = fs::read(&tmp_path).await?;
+                state
+                    .store
+                    .save(&upload_key, Bytes::from(data))
+                    .await
+                    .map_err(actix_web::error::ErrorInternalServerError)?;
+            }
+            fs::remove_file(&tmp_path).await.ok();
+
+            content_hash = hash;
+            stored_key = Some(upload_key);
         } else {
             return Ok(HttpResponse::BadRequest().body("No filename provided in the request"));
         }
     }
 
-    if let Some(input_file_path) = file_path {
-        let mut task_id_counter = state.task_id_counter.lock().unwrap();
-        let task_id = *task_id_counter + 1;
-        *task_id_counter = task_id;
+    if let Some(input_key) = stored_key {
+        let cache_key = format!("{}:{}", content_hash, output_format);
+        let task_id = state.next_task_id.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(cached) = state.db.get(cache_key.as_bytes()).unwrap() {
+            let output_key = String::from_utf8(cached.to_vec()).unwrap();
+            let filename = output_key.rsplit('/').next().unwrap_or(&output_key);
+            state.tasks.set(
+                task_id,
+                TaskStatus::Completed {
+                    download_url: format!("/download/{}", filename),
+                    delete_token: generate_delete_token(),
+                    output_key,
+                    cache_key,
+                    content_hash: content_hash.clone(),
+                },
+            );
+        } else {
+            let output_key = format!("downloads/{}.{}", content_hash, output_format);
+            state.tasks.set(task_id, TaskStatus::Queued);
 
-        let output_file_path = generate_output_filepath(&original_filename, output_format, task_id);
+            let job = ConvertJob {
+                task_id,
+                input_key,
+                output_key,
+                output_format: output_format.clone(),
+                cache_key,
+                content_hash: content_hash.clone(),
+            };
 
-        match convert_image(&input_file_path, output_format, &output_file_path).await {
-            Ok(_) => Ok(HttpResponse::Ok().json({
-                serde_json::json!({
-                    "task_id": task_id,
-                    "converted_file": format!("/download/{}", output_file_path.file_name().unwrap().to_str().unwrap()),
-                })
-            })),
-            Err(e) => Ok(HttpResponse::InternalServerError().body(format!("Conversion failed: {}", e))),
+            if state.job_tx.send(job).await.is_err() {
+                return Ok(HttpResponse::InternalServerError().body("Conversion worker unavailable"));
+            }
         }
+
+        Ok(HttpResponse::Accepted().json(serde_json::json!({
+            "task_id": task_id,
+            "content_hash": content_hash,
+            "status": format!("/status/{}", task_id),
+        })))
     } else {
         Ok(HttpResponse::BadRequest().body("No file uploaded"))
     }
 }
 
+#[get("/status/{task_id}")]
+async fn task_status_endpoint(state: web::Data<AppState>, task_id: web::Path<u64>) -> Result<HttpResponse> {
+    match state.tasks.get(task_id.into_inner()) {
+        Some(status) => Ok(HttpResponse::Ok().json(status)),
+        None => Ok(HttpResponse::NotFound().body("Unknown task id")),
+    }
+}
+
+/// Reclaims a completed task's converted output (and its source upload, if no
+/// other converted variant still references the same content hash).
+#[actix_web::delete("/delete/{task_id}/{token}")]
+async fn delete_task_endpoint(
+    state: web::Data<AppState>,
+    path: web::Path<(u64, String)>,
+) -> Result<HttpResponse> {
+    let (task_id, token) = path.into_inner();
+
+    let (output_key, cache_key, content_hash) = match state.tasks.get(task_id) {
+        None => return Ok(HttpResponse::NotFound().body("Unknown task id")),
+        Some(TaskStatus::Completed {
+            delete_token,
+            output_key,
+            cache_key,
+            content_hash,
+            ..
+        }) => {
+            if !tokens_match(&token, &delete_token) {
+                return Ok(HttpResponse::Forbidden().body("Invalid delete token"));
+            }
+            (output_key, cache_key, content_hash)
+        }
+        Some(_) => return Ok(HttpResponse::NotFound().body("Unknown task id")),
+    };
+
+    // Other completed tasks (from the same or a different client re-uploading
+    // identical content+format) can share this cache_key/output_key via the
+    // chunk0-1 dedup path, so only reclaim the converted file once this was
+    // the last task referencing it.
+    if !state.tasks.other_completed_references(&cache_key, task_id) {
+        state
+            .store
+            .delete(&output_key)
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        state.db.remove(cache_key.as_bytes()).unwrap();
+    }
+
+    let upload_key = format!("uploads/{}", content_hash);
+    let hash_prefix = format!("{}:", content_hash);
+    let other_variants_remain = state
+        .db
+        .scan_prefix(hash_prefix.as_bytes())
+        .next()
+        .is_some();
+    if !other_variants_remain {
+        state
+            .store
+            .delete(&upload_key)
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+    }
+
+    state.tasks.remove(task_id);
+
+    Ok(HttpResponse::Ok().body("Deleted"))
+}
+
+fn uuid_like() -> String {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    format!("{:x}-{:x}", nanos, std::process::id())
+}
+
+fn output_format_to_image_format(output_format: &str) -> Option<ImageFormat> {
+    match output_format {
+        "png" => Some(ImageFormat::Png),
+        "jpg" => Some(ImageFormat::Jpeg),
+        "gif" => Some(ImageFormat::Gif),
+        "bmp" => Some(ImageFormat::Bmp),
+        "webp" => Some(ImageFormat::WebP),
+        "ico" => Some(ImageFormat::Ico),
+        "tiff" => Some(ImageFormat::Tiff),
+        _ => None,
+    }
+}
+
+/// Content hashes are hex-encoded SHA-256 digests, always 64 lowercase hex
+/// characters. Rejecting anything else before it's joined onto a store path
+/// keeps values like `..` or an absolute path from ever being resolved.
+const SHA256_HEX_LEN: usize = 64;
+
+fn is_valid_content_hash(hash: &str) -> bool {
+    hash.len() == SHA256_HEX_LEN && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Conversion outputs are always named `<hash>.<format>` (see
+/// `convert_image_endpoint`/`run_job`), so a download filename that doesn't fit
+/// that shape is either stale or an attempt to smuggle a `..`/`/` path segment
+/// through `FsStore::resolve`. Reject it instead of trusting the raw segment.
+fn is_valid_download_filename(filename: &str) -> bool {
+    match filename.split_once('.') {
+        Some((hash, ext)) => {
+            is_valid_content_hash(hash) && !ext.is_empty() && ext.bytes().all(|b| b.is_ascii_alphanumeric())
+        }
+        None => false,
+    }
+}
+
+/// Serves a processed variant of a stored upload, materializing and caching it
+/// on first request. `chain` is a slash-separated sequence of operations, e.g.
+/// `resize/320/blur/5`.
+#[get("/process/{hash}/{chain:.*}.{ext}")]
+async fn process_image_endpoint(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String, String)>,
+) -> Result<HttpResponse> {
+    let (hash, chain, ext) = path.into_inner();
+
+    if !is_valid_content_hash(&hash) {
+        return Err(actix_web::error::ErrorBadRequest("Malformed content hash"));
+    }
+
+    let input_key = format!("uploads/{}", hash);
+    if !state.store.exists(&input_key).await {
+        return Err(actix_web::error::ErrorNotFound("Unknown content hash"));
+    }
+
+    let ops = match parse_chain(&chain) {
+        Ok(ops) => ops,
+        Err(e) => return Err(actix_web::error::ErrorBadRequest(e)),
+    };
+
+    let image_format = match output_format_to_image_format(&ext) {
+        Some(f) => f,
+        None => return Err(actix_web::error::ErrorBadRequest("Unsupported output format")),
+    };
+
+    let variant_key = format!("downloads/process/{}__{}.{}", hash, canonical_key(&ops), ext);
+
+    if !state.store.exists(&variant_key).await {
+        let (input_path, input_is_scratch) = materialize_local(state.store.as_ref(), &input_key)
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+
+        let reader = ImageReader::open(&input_path)
+            .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Failed to open source image: {:?}", e)))?;
+        let img = reader
+            .decode()
+            .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Failed to decode source image: {:?}", e)))?;
+
+        if input_is_scratch {
+            fs::remove_file(&input_path).await.ok();
+        }
+
+        let (variant_path, variant_is_scratch) = match state.store.local_path(&variant_key) {
+            Some(path) => (path, false),
+            None => (unique_scratch_path(&variant_key), true),
+        };
+        if let Some(parent) = variant_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let processed = apply_chain(img, &ops);
+        processed
+            .save_with_format(&variant_path, image_format)
+            .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Failed to save processed image: {:?}", e)))?;
+
+        finalize_local(state.store.as_ref(), &variant_key, &variant_path)
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?;
 
-fn generate_output_filepath(filename: &str, output_format: &str, task_id: i32) -> PathBuf {
-    let stem = Path::new(filename).file_stem().unwrap().to_str().unwrap();
-    Path::new("downloads").join(format!("{}_{}.{}", stem, task_id, output_format))
+        if variant_is_scratch {
+            fs::remove_file(&variant_path).await.ok();
+        }
+    }
+
+    serve_store_key(&req, state.store.as_ref(), &variant_key).await
 }
 
-// Serve the converted image files
+// Serve the converted image files. With the local-disk store, conditional and
+// range requests are honored so large TIFF/BMP outputs can be fetched in chunks
+// and cached by CDNs; other backends always send the whole file.
 #[get("/download/{filename}")]
-async fn serve_converted_image(filename: web::Path<String>) -> Result<NamedFile> {
-    let path = Path::new("downloads").join(filename.into_inner());
-    
-    println!("Serving file from path: {:?}", path);
+async fn serve_converted_image(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    filename: web::Path<String>,
+) -> Result<HttpResponse> {
+    let filename = filename.into_inner();
+    if !is_valid_download_filename(&filename) {
+        return Err(actix_web::error::ErrorBadRequest("Malformed filename"));
+    }
 
-    if path.exists() {
-        Ok(NamedFile::open(path)?)
-    } else {
-        println!("File not found: {:?}", path);
-        Err(actix_web::error::ErrorNotFound("File not found"))
+    let key = format!("downloads/{}", filename);
+    serve_store_key(&req, state.store.as_ref(), &key).await
+}
+
+/// Shared by the download and process endpoints: when the backend keeps a real
+/// local file (`FsStore`), hand it to `NamedFile` for range/conditional-GET
+/// support; otherwise stream the whole object body straight from the store
+/// with a 200. `Range` and conditional headers are not honored on that path.
+async fn serve_store_key(req: &HttpRequest, store: &dyn Store, key: &str) -> Result<HttpResponse> {
+    if let Some(path) = store.local_path(key) {
+        if !path.exists() {
+            return Err(actix_web::error::ErrorNotFound("File not found"));
+        }
+
+        let named_file = NamedFile::open(path)?.use_last_modified(true).use_etag(true);
+        let mut response = named_file.into_response(req);
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static("public, max-age=31536000, immutable"),
+        );
+        return Ok(response);
     }
+
+    let stream = store
+        .load(key)
+        .await
+        .map_err(|_| actix_web::error::ErrorNotFound("File not found"))?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=31536000, immutable"))
+        .streaming(stream.map_err(actix_web::error::ErrorInternalServerError)))
 }
 
-async fn convert_image(input_file: &Path, output_format: &str, output_file: &Path) -> Result<(), String> {
+pub(crate) async fn convert_image(input_file: &Path, output_format: &str, output_file: &Path) -> Result<(), String> {
     println!("Converting image from {:?} to {:?}", input_file, output_format);
 
     let mut img = match ImageReader::open(input_file) {
@@ -110,16 +411,11 @@ async fn convert_image(input_file: &Path, output_format: &str, output_file: &Pat
         img = img.thumbnail(256, 256);
     }
 
-    let result = match output_format {
-        "png" => img.save_with_format(output_file, ImageFormat::Png),
-        "jpg" => img.save_with_format(output_file, ImageFormat::Jpeg),
-        "gif" => img.save_with_format(output_file, ImageFormat::Gif),
-        "bmp" => img.save_with_format(output_file, ImageFormat::Bmp),
-        "webp" => img.save_with_format(output_file, ImageFormat::WebP),
-        "ico" => img.save_with_format(output_file, ImageFormat::Ico),
-        "tiff" => img.save_with_format(output_file, ImageFormat::Tiff),
-        _ => return Err("Unsupported output format".to_string()),
+    let image_format = match output_format_to_image_format(output_format) {
+        Some(f) => f,
+        None => return Err("Unsupported output format".to_string()),
     };
+    let result = img.save_with_format(output_file, image_format);
 
     if let Err(e) = result {
         println!("Failed to save output image: {:?}", e);
@@ -146,8 +442,19 @@ async fn main() -> std::io::Result<()> {
     fs::create_dir_all("downloads").await?;
 
     // Define the application state
+    let db = sled::open("db").expect("failed to open sled db");
+    let tasks = Arc::new(TaskRegistry::new());
+    let (job_tx, job_rx) = mpsc::channel::<ConvertJob>(128);
+    let store: Arc<dyn Store> = Arc::from(build_store("."));
+
+    tokio::spawn(queue::run_worker(job_rx, tasks.clone(), db.clone(), store.clone()));
+
     let state = web::Data::new(AppState {
-        task_id_counter: Mutex::new(0),
+        db,
+        tasks,
+        next_task_id: AtomicU64::new(1),
+        job_tx,
+        store,
     });
 
     HttpServer::new(move || {
@@ -155,6 +462,9 @@ async fn main() -> std::io::Result<()> {
             .app_data(state.clone()) // This passes the state to the application
             .wrap(Cors::permissive()) // Use permissive CORS for development
             .service(convert_image_endpoint)
+            .service(task_status_endpoint)
+            .service(delete_task_endpoint)
+            .service(process_image_endpoint)
             .service(serve_converted_image)
     })
     .bind("0.0.0.0:8000")?  // Bind to all available IP addresses
@@ -0,0 +1,166 @@
+use image::DynamicImage;
+
+/// Dimensions outside this range are rejected before any resizing work happens,
+/// so a client can't ask us to allocate an arbitrarily huge buffer.
+const MIN_DIMENSION: u32 = 1;
+const MAX_DIMENSION: u32 = 4096;
+const MAX_BLUR_SIGMA: f32 = 50.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcessOp {
+    Resize(u32),
+    Thumbnail(u32),
+    Crop(u32, u32, u32, u32),
+    Blur(f32),
+}
+
+impl ProcessOp {
+    /// Re-serializes the op the same way regardless of how it was spelled in the
+    /// request, so equivalent chains (extra slashes, leading zeros, ...) share a
+    /// cache entry instead of each minting their own variant file.
+    fn canonical(&self) -> String {
+        match self {
+            ProcessOp::Resize(n) => format!("resize/{}", n),
+            ProcessOp::Thumbnail(n) => format!("thumbnail/{}", n),
+            ProcessOp::Crop(x, y, w, h) => format!("crop/{}/{}/{}/{}", x, y, w, h),
+            ProcessOp::Blur(sigma) => format!("blur/{}", sigma),
+        }
+    }
+}
+
+pub fn parse_chain(chain: &str) -> Result<Vec<ProcessOp>, String> {
+    let tokens: Vec<&str> = chain.split('/').filter(|t| !t.is_empty()).collect();
+    let mut ops = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        match tokens[i] {
+            "resize" => {
+                let n = parse_dimension(tokens.get(i + 1))?;
+                ops.push(ProcessOp::Resize(n));
+                i += 2;
+            }
+            "thumbnail" => {
+                let n = parse_dimension(tokens.get(i + 1))?;
+                ops.push(ProcessOp::Thumbnail(n));
+                i += 2;
+            }
+            "crop" => {
+                let x = parse_u32(tokens.get(i + 1))?;
+                let y = parse_u32(tokens.get(i + 2))?;
+                let w = parse_dimension(tokens.get(i + 3))?;
+                let h = parse_dimension(tokens.get(i + 4))?;
+                ops.push(ProcessOp::Crop(x, y, w, h));
+                i += 5;
+            }
+            "blur" => {
+                let sigma: f32 = tokens
+                    .get(i + 1)
+                    .ok_or("blur requires a sigma argument")?
+                    .parse()
+                    .map_err(|_| "blur sigma must be a number".to_string())?;
+                if sigma.is_nan() || sigma < 0.0 || sigma > MAX_BLUR_SIGMA {
+                    return Err(format!("blur sigma must be between 0 and {}", MAX_BLUR_SIGMA));
+                }
+                ops.push(ProcessOp::Blur(sigma));
+                i += 2;
+            }
+            other => return Err(format!("unknown operation: {}", other)),
+        }
+    }
+
+    if ops.is_empty() {
+        return Err("processing chain must contain at least one operation".to_string());
+    }
+
+    Ok(ops)
+}
+
+fn parse_u32(token: Option<&&str>) -> Result<u32, String> {
+    token
+        .ok_or("missing argument")?
+        .parse()
+        .map_err(|_| "argument must be a non-negative integer".to_string())
+}
+
+fn parse_dimension(token: Option<&&str>) -> Result<u32, String> {
+    let n = parse_u32(token)?;
+    if n < MIN_DIMENSION || n > MAX_DIMENSION {
+        return Err(format!(
+            "dimension {} out of range ({}-{})",
+            n, MIN_DIMENSION, MAX_DIMENSION
+        ));
+    }
+    Ok(n)
+}
+
+/// Canonical cache key for a parsed chain, safe to use as a filename component.
+pub fn canonical_key(ops: &[ProcessOp]) -> String {
+    ops.iter()
+        .map(ProcessOp::canonical)
+        .collect::<Vec<_>>()
+        .join("_")
+        .replace('/', "-")
+}
+
+pub fn apply_chain(mut img: DynamicImage, ops: &[ProcessOp]) -> DynamicImage {
+    for op in ops {
+        img = match *op {
+            ProcessOp::Resize(n) => img.resize(n, n, image::imageops::FilterType::Lanczos3),
+            ProcessOp::Thumbnail(n) => img.thumbnail(n, n),
+            ProcessOp::Crop(x, y, w, h) => img.crop_imm(x, y, w, h),
+            ProcessOp::Blur(sigma) => img.blur(sigma),
+        };
+    }
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_valid_chain() {
+        let ops = parse_chain("resize/320/blur/5").unwrap();
+        assert_eq!(ops, vec![ProcessOp::Resize(320), ProcessOp::Blur(5.0)]);
+    }
+
+    #[test]
+    fn parses_crop_and_thumbnail() {
+        let ops = parse_chain("crop/10/20/100/200/thumbnail/64").unwrap();
+        assert_eq!(
+            ops,
+            vec![ProcessOp::Crop(10, 20, 100, 200), ProcessOp::Thumbnail(64)]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_operation() {
+        let err = parse_chain("sharpen/5").unwrap_err();
+        assert!(err.contains("unknown operation"));
+    }
+
+    #[test]
+    fn rejects_out_of_range_dimension() {
+        let err = parse_chain("resize/999999").unwrap_err();
+        assert!(err.contains("out of range"));
+    }
+
+    #[test]
+    fn rejects_nan_blur_sigma() {
+        let err = parse_chain("blur/nan").unwrap_err();
+        assert!(err.contains("must be between"));
+    }
+
+    #[test]
+    fn rejects_missing_argument() {
+        let err = parse_chain("resize").unwrap_err();
+        assert_eq!(err, "missing argument");
+    }
+
+    #[test]
+    fn rejects_empty_chain() {
+        let err = parse_chain("").unwrap_err();
+        assert!(err.contains("at least one operation"));
+    }
+}
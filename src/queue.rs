@@ -0,0 +1,179 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+use crate::convert_image;
+use crate::store::{finalize_local, materialize_local, unique_scratch_path, Store};
+
+pub struct ConvertJob {
+    pub task_id: u64,
+    pub input_key: String,
+    pub output_key: String,
+    pub output_format: String,
+    pub cache_key: String,
+    pub content_hash: String,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "status")]
+pub enum TaskStatus {
+    Queued,
+    Processing,
+    Completed {
+        download_url: String,
+        delete_token: String,
+        #[serde(skip_serializing)]
+        output_key: String,
+        #[serde(skip_serializing)]
+        cache_key: String,
+        #[serde(skip_serializing)]
+        content_hash: String,
+    },
+    Failed { error: String },
+}
+
+pub fn generate_delete_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect()
+}
+
+/// Bitwise-OR over every byte pair so the comparison takes the same number of
+/// steps regardless of where the strings first differ.
+pub fn tokens_match(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    diff == 0
+}
+
+pub struct TaskRegistry {
+    tasks: Mutex<HashMap<u64, TaskStatus>>,
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        TaskRegistry {
+            tasks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set(&self, task_id: u64, status: TaskStatus) {
+        self.tasks.lock().unwrap().insert(task_id, status);
+    }
+
+    pub fn get(&self, task_id: u64) -> Option<TaskStatus> {
+        self.tasks.lock().unwrap().get(&task_id).cloned()
+    }
+
+    pub fn remove(&self, task_id: u64) {
+        self.tasks.lock().unwrap().remove(&task_id);
+    }
+
+    /// True if some *other* completed task still points at `cache_key` — i.e.
+    /// the underlying converted file is shared and must not be deleted out
+    /// from under that task.
+    pub fn other_completed_references(&self, cache_key: &str, excluding_task_id: u64) -> bool {
+        self.tasks.lock().unwrap().iter().any(|(id, status)| {
+            *id != excluding_task_id
+                && matches!(status, TaskStatus::Completed { cache_key: ck, .. } if ck == cache_key)
+        })
+    }
+}
+
+/// Pulls conversion jobs off `rx` and runs them one at a time, recording the
+/// outcome in `tasks` and the sled cache so later `/convert` calls for the
+/// same (hash, format) pair can short-circuit. Reads and writes go through
+/// `store`, so this works unmodified whether the backend is local disk or S3.
+pub async fn run_worker(
+    mut rx: mpsc::Receiver<ConvertJob>,
+    tasks: std::sync::Arc<TaskRegistry>,
+    db: sled::Db,
+    store: std::sync::Arc<dyn Store>,
+) {
+    while let Some(job) = rx.recv().await {
+        tasks.set(job.task_id, TaskStatus::Processing);
+
+        match run_job(&job, store.as_ref()).await {
+            Ok(_) => {
+                let _ = db.insert(job.cache_key.as_bytes(), job.output_key.as_bytes());
+                let _ = db.flush_async().await;
+
+                let filename = job.output_key.rsplit('/').next().unwrap_or(&job.output_key);
+                tasks.set(
+                    job.task_id,
+                    TaskStatus::Completed {
+                        download_url: format!("/download/{}", filename),
+                        delete_token: generate_delete_token(),
+                        output_key: job.output_key.clone(),
+                        cache_key: job.cache_key.clone(),
+                        content_hash: job.content_hash.clone(),
+                    },
+                );
+            }
+            Err(error) => {
+                tasks.set(job.task_id, TaskStatus::Failed { error });
+            }
+        }
+    }
+}
+
+async fn run_job(job: &ConvertJob, store: &dyn Store) -> Result<(), String> {
+    let (input_path, input_is_scratch) = materialize_local(store, &job.input_key)
+        .await
+        .map_err(|e| format!("Failed to fetch input from store: {:?}", e))?;
+
+    let (output_path, output_is_scratch) = match store.local_path(&job.output_key) {
+        Some(path) => (path, false),
+        None => (unique_scratch_path(&job.output_key), true),
+    };
+    if let Some(parent) = output_path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| format!("Failed to create output directory: {:?}", e))?;
+    }
+
+    let result = convert_image(&input_path, &job.output_format, &output_path).await;
+
+    if input_is_scratch {
+        let _ = tokio::fs::remove_file(&input_path).await;
+    }
+
+    result?;
+
+    finalize_local(store, &job.output_key, &output_path)
+        .await
+        .map_err(|e| format!("Failed to persist output to store: {:?}", e))?;
+
+    if output_is_scratch {
+        let _ = tokio::fs::remove_file(&output_path).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_equal_tokens() {
+        assert!(tokens_match("abc123XYZ", "abc123XYZ"));
+    }
+
+    #[test]
+    fn rejects_different_length_tokens() {
+        assert!(!tokens_match("short", "muchlongertoken"));
+    }
+
+    #[test]
+    fn rejects_single_bit_difference() {
+        assert!(!tokens_match("abc123XYZ", "abc123XYz"));
+    }
+}
@@ -0,0 +1,235 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::{Stream, StreamExt};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rusty_s3::{actions::S3Action, Bucket, Credentials, UrlStyle};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+const PRESIGN_TTL: Duration = Duration::from_secs(60);
+
+/// Backing object store for uploads and converted outputs. `save`/`load`/`exists`
+/// are keyed by the same relative paths the rest of the app already uses, e.g.
+/// `uploads/<hash>` or `downloads/<hash>.<format>`, so swapping implementations
+/// doesn't change how callers name things.
+#[async_trait]
+pub trait Store: Send + Sync {
+    async fn save(&self, key: &str, bytes: Bytes) -> std::io::Result<()>;
+    async fn load(&self, key: &str) -> std::io::Result<ByteStream>;
+    async fn exists(&self, key: &str) -> bool;
+    async fn delete(&self, key: &str) -> std::io::Result<()>;
+
+    /// Fast path for backends that keep a real file on local disk (only
+    /// `FsStore` does). Callers can use this to hand the file straight to
+    /// `NamedFile` for range/conditional-GET support instead of streaming it.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FsStore { root: root.into() }
+    }
+
+    fn resolve(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl Store for FsStore {
+    async fn save(&self, key: &str, bytes: Bytes) -> std::io::Result<()> {
+        let path = self.resolve(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, bytes).await
+    }
+
+    async fn load(&self, key: &str) -> std::io::Result<ByteStream> {
+        let file = tokio::fs::File::open(self.resolve(key)).await?;
+        let stream = tokio_util::io::ReaderStream::new(file).map(|chunk| chunk.map_err(Into::into));
+        Ok(Box::pin(stream))
+    }
+
+    async fn exists(&self, key: &str) -> bool {
+        tokio::fs::metadata(self.resolve(key)).await.is_ok()
+    }
+
+    async fn delete(&self, key: &str) -> std::io::Result<()> {
+        match tokio::fs::remove_file(self.resolve(key)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.resolve(key))
+    }
+}
+
+pub struct S3Store {
+    bucket: Bucket,
+    credentials: Credentials,
+    client: reqwest::Client,
+}
+
+impl S3Store {
+    pub fn from_env() -> Self {
+        let endpoint = env_var("S3_ENDPOINT");
+        let region = env_var("S3_REGION");
+        let bucket_name = env_var("S3_BUCKET");
+        let key_id = env_var("S3_ACCESS_KEY_ID");
+        let secret = env_var("S3_SECRET_ACCESS_KEY");
+
+        let bucket = Bucket::new(
+            endpoint.parse().expect("invalid S3_ENDPOINT"),
+            UrlStyle::Path,
+            bucket_name,
+            region,
+        )
+        .expect("invalid S3 bucket configuration");
+        let credentials = Credentials::new(key_id, secret);
+
+        S3Store {
+            bucket,
+            credentials,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+fn env_var(name: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| panic!("{} must be set when STORAGE_BACKEND=s3", name))
+}
+
+#[async_trait]
+impl Store for S3Store {
+    async fn save(&self, key: &str, bytes: Bytes) -> std::io::Result<()> {
+        let action = self.bucket.put_object(Some(&self.credentials), key);
+        let url = action.sign(PRESIGN_TTL);
+
+        let resp = self
+            .client
+            .put(url)
+            .body(bytes)
+            .send()
+            .await
+            .map_err(to_io_error)?;
+
+        if !resp.status().is_success() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("S3 PUT failed with status {}", resp.status()),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn load(&self, key: &str) -> std::io::Result<ByteStream> {
+        let action = self.bucket.get_object(Some(&self.credentials), key);
+        let url = action.sign(PRESIGN_TTL);
+
+        let resp = self.client.get(url).send().await.map_err(to_io_error)?;
+        if !resp.status().is_success() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("S3 GET failed with status {}", resp.status()),
+            ));
+        }
+
+        let stream = resp.bytes_stream().map(|chunk| chunk.map_err(to_io_error));
+        Ok(Box::pin(stream))
+    }
+
+    async fn exists(&self, key: &str) -> bool {
+        let action = self.bucket.head_object(Some(&self.credentials), key);
+        let url = action.sign(PRESIGN_TTL);
+
+        matches!(self.client.head(url).send().await, Ok(resp) if resp.status().is_success())
+    }
+
+    async fn delete(&self, key: &str) -> std::io::Result<()> {
+        let action = self.bucket.delete_object(Some(&self.credentials), key);
+        let url = action.sign(PRESIGN_TTL);
+
+        let resp = self.client.delete(url).send().await.map_err(to_io_error)?;
+        if !resp.status().is_success() && resp.status() != reqwest::StatusCode::NOT_FOUND {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("S3 DELETE failed with status {}", resp.status()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn to_io_error(e: reqwest::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e)
+}
+
+/// Builds the configured backend. `STORAGE_BACKEND=s3` (plus `S3_*` env vars)
+/// selects `S3Store`; anything else (including unset) keeps the historical
+/// local-disk layout under `root`.
+pub fn build_store(root: impl Into<PathBuf>) -> Box<dyn Store> {
+    match std::env::var("STORAGE_BACKEND").as_deref() {
+        Ok("s3") => Box::new(S3Store::from_env()),
+        _ => Box::new(FsStore::new(root)),
+    }
+}
+
+/// Scratch paths are only ever derived from a store key for non-`FsStore`
+/// backends, so without a per-call unique component, two concurrent requests
+/// producing the same key (e.g. duplicate `/convert` calls for the same
+/// hash+format) would read, write, and delete the same file out from under
+/// each other.
+pub fn unique_scratch_path(key: &str) -> PathBuf {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    PathBuf::from("scratch").join(format!("{}-{}", suffix, key.replace('/', "_")))
+}
+
+/// The `image` crate needs a real, seekable file to decode from. For `FsStore`
+/// that's just `local_path`; for a remote backend we stream the object down to
+/// a scratch file first. The `bool` tells the caller whether it owns a scratch
+/// file it must clean up afterwards.
+pub async fn materialize_local(store: &dyn Store, key: &str) -> std::io::Result<(PathBuf, bool)> {
+    if let Some(path) = store.local_path(key) {
+        return Ok((path, false));
+    }
+
+    tokio::fs::create_dir_all("scratch").await?;
+    let scratch_path = unique_scratch_path(key);
+
+    let mut stream = store.load(key).await?;
+    let mut file = tokio::fs::File::create(&scratch_path).await?;
+    while let Some(chunk) = stream.next().await {
+        tokio::io::AsyncWriteExt::write_all(&mut file, &chunk?).await?;
+    }
+    file.sync_all().await?;
+
+    Ok((scratch_path, true))
+}
+
+/// Uploads a locally-produced file to `key` unless it's already sitting at the
+/// right place (true for `FsStore`, whose `local_path` *is* the final key).
+pub async fn finalize_local(store: &dyn Store, key: &str, local_path: &std::path::Path) -> std::io::Result<()> {
+    if store.local_path(key).is_some() {
+        return Ok(());
+    }
+    let bytes = tokio::fs::read(local_path).await?;
+    store.save(key, bytes.into()).await
+}